@@ -1,99 +1,6 @@
-#[allow(dead_code)]
-#[repr(u32)]
-enum X11WmState {
-    Withdrawn = 0,
-    Normal    = 1,
-    //             = 2,
-    Iconic    = 3,
-}
-
-//Getting a handle for /proc and the pid of the focused window seem like orthogonal tasks - and they are
-//however, we want to avoid having our view of /proc being inconsistent with the window's pid.
-//If we open /proc before we get the pid, but the program is started after we got our /proc-handle,
-//we won't find the process with our handle (or potentially worse, scan a program that just exited
-//and happened to have the same pid).
-//If we open /proc after we get the pid, the program might be closed before we get our proc-handle,
-//resulting in a similar unfortunate scenario.
-//Instead we open /proc after getting the window handle, but before getting its pid. I'm not sure
-//however if getting the pid actually does fail if we try it with a stale window handle.
-fn get_proc_and_focused_window_pid() -> Result<(openat::Dir, u32), String> {
-    //FIXME: X11 endianess?
-    use byteorder::{LittleEndian, ReadBytesExt};
-
-    let (conn, screen_num) = xcb::Connection::connect(None).map_err(|error| format!("Unable to open X11 connection: {}.", error))?;
-
-    let active_window_atom_cookie = xcb::intern_atom(&conn, false, "_NET_ACTIVE_WINDOW");
-    let pid_atom_cookie           = xcb::intern_atom(&conn, false, "_NET_WM_PID");
-    let state_atom_cookie         = xcb::intern_atom(&conn, false, "WM_STATE");
-
-    //get window
-    let root = conn.get_setup().roots().nth(screen_num as usize)
-                   .ok_or_else(|| "Unable to select current screen.".to_string())?.root();
-
-    let active_window_atom = active_window_atom_cookie.get_reply().map_err(|error| format!("Unable to retrieve _NET_ACTIVE_WINDOW atom: {}.", error))?.atom();
-
-    let reply = xcb::get_property(&conn, false, root, active_window_atom, xcb::ATOM_WINDOW, 0, 1)
-                    .get_reply()
-                    .map_err(|error| format!("Unable to retrieve _NET_ACTIVE_WINDOW property from root: {}.", error))?;
-    if reply.value_len() == 0 {
-        return Err("Unable to retrieve _NET_ACTIVE_WINDOW property from root.".to_string());
-    }
-    assert_eq!(reply.value_len(), 1);
-    let mut raw = reply.value();
-    assert_eq!(raw.len(), 4, "_NET_ACTIVE_WINDOW property is expected to be at least 4 bytes.");
-    let window = raw.read_u32::<LittleEndian>().unwrap() as xcb::Window;
-    if window == xcb::WINDOW_NONE {
-        return Err("No window is focused".to_string());
-    }
-
-    //open proc
-    let proc = openat::Dir::open("/proc").map_err(|error| format!("Unable to open /proc: {}", error))?;
-
-    //check withdrawn state
-    let state_atom = state_atom_cookie.get_reply().map_err(|error| format!("Unable to retrieve WM_STATE atom: {}.", error))?.atom();
+mod window_system;
 
-    match xcb::get_property(&conn, false, window, state_atom, state_atom, 0, 1).get_reply() {
-        Ok(reply) => {
-            if reply.value_len() == 0 {
-                eprintln!("Unable to retrieve WM_STATE from focused window {}.", window);
-            }
-            else {
-                assert_eq!(reply.value_len(), 1);
-                let mut raw = reply.value();
-                assert_eq!(raw.len(), 4, "WM_STATE property is expected to be at least 4 bytes.");
-                let state = raw.read_u32::<LittleEndian>().unwrap();
-                if state != X11WmState::Normal as u32 {
-                    return Err(format!("Focused window {} is not in normal (visible) state ({} != {}); Ignoring.", window, state, X11WmState::Normal as u32));
-                }
-            }
-        }
-        Err(error) => {
-            eprintln!("Unable to retrieve WM_STATE from focused window {}: {}", window, error);
-        }
-    };
-
-    //get pid
-    let pid_atom = pid_atom_cookie.get_reply().map_err(|error| format!("Unable to retrieve _NET_WM_PID: {}.", error))?.atom();
-
-    let reply = xcb::get_property(&conn, false, window, pid_atom, xcb::ATOM_CARDINAL, 0, 1)
-                    .get_reply()
-                    .unwrap_or_else(|error| panic!("Unable to retrieve _NET_WM_PID from focused window {}: {}", window, error));
-    if reply.value_len() == 0 {
-        eprintln!("Unable to retrieve _NET_WM_PID from focused window {}; trying WM_CLASS.", window);
-        //TODO: what's a good size here?
-        let reply = xcb::get_property(&conn, false, window, xcb::ATOM_WM_CLASS, xcb::ATOM_STRING, 0, 64)
-                        .get_reply()
-                        .unwrap_or_else(|error| panic!("Unable to retrieve WM_CLASS from focused window {}: {}", window, error));
-        let class = String::from_utf8(reply.value().iter().cloned().take_while(|c| *c != 0u8).collect::<Vec<_>>())
-                           .unwrap_or_else(|error| panic!("Unable to decode {:#?}: {}", reply.value() as &[u8], error));
-        //TODO: find processes named 'class', compare cwds
-        return Err(format!("Unimplemented: Find processes named {}", class));
-    }
-    assert_eq!(reply.value_len(), 1);
-    let mut raw = reply.value();
-    assert_eq!(raw.len(), 4, "_NET_WM_PID property is expected to be at least 4 bytes");
-    Ok((proc, raw.read_u32::<LittleEndian>().unwrap()))
-}
+use window_system::WindowSystem;
 
 enum Cwd {
     Regular(String),
@@ -132,6 +39,22 @@ impl Into<String> for Cwd {
     }
 }
 
+//The kernel tracks which process group a tty's controlling terminal currently considers
+//"foreground" (field 8, tpgid, of /proc/<pid>/stat). That's a much better signal for "which
+//child is active" than just picking whichever child happens to be listed first.
+//Returns None if there is no controlling tty (tpgid == -1) or the field can't be parsed.
+fn foreground_pid(proc: &openat::Dir, pid: u32) -> Option<u32> {
+    use std::io::Read;
+
+    let mut stat = String::new();
+    proc.open_file(format!("{}/stat", pid)).ok()?.read_to_string(&mut stat).ok()?;
+    //comm (field 2) is parenthesized and may itself contain spaces/parens, so skip past the
+    //last ')' before splitting the remaining space-separated fields
+    let after_comm = stat.rfind(')')? + 1;
+    let tpgid = stat[after_comm..].split_whitespace().nth(5)?.parse::<i32>().ok()?;
+    if tpgid > 0 { Some(tpgid as u32) } else { None }
+}
+
 fn get_child_cwd<Str: PartialEq<str>>(proc: &openat::Dir, pid: u32, priority_commands: &[Str]) -> Result<Cwd, String> {
     use std::io::Read;
 
@@ -158,33 +81,41 @@ fn get_child_cwd<Str: PartialEq<str>>(proc: &openat::Dir, pid: u32, priority_com
 
     //get child cwd
     debug_assert!(children == children.trim_start());
-    //make children an iterator of (pid, cwd) for every valid cwd
-    //"for every valid cwd" means that Cwd::exists_or_err() should return Ok(_)
-    let mut children = children.trim_end().split(' ').filter_map(|child| {
+    //collect (pid, cwd) for every child with a valid cwd
+    //"valid cwd" means that Cwd::exists_or_err() should return Ok(_)
+    let children: Vec<(u32, Cwd)> = children.trim_end().split(' ').filter_map(|child| {
         let pid = child.parse().unwrap();
         get_child_cwd(proc, pid, priority_commands).ok().map(|cwd| (pid, cwd))
-    });
-    let child_cwd = if let Some((child_pid, child_cwd)) = children.next() {
-        let mut children = children.peekable();
-        if children.peek().is_some() {
-            //TODO: this isn't a problem if all children have the same cwd
-            eprintln!("Warning: Process {} has multiple children. Following {}.", pid, child_pid);
-        }
-        match child_cwd {
-            Cwd::Regular(_) => {
-                //try for a priority cwd in its place
-                //but if we don't find a priority command we continue with the non-prioritized child
-                children.map(|(_, cwd)| cwd).find(|cwd| match cwd {
-                    Cwd::Priority(_) => true,
-                    Cwd::Regular(_) => false,
-                }).unwrap_or(child_cwd)
-            }
-            //if it's already a priority command...
-            Cwd::Priority(_) => child_cwd,
-        }
-    } else {
+    }).collect();
+    let child_cwd = if children.is_empty() {
         //children have no valid cwd
         return cwd.exists_or_err();
+    } else if children.len() == 1 {
+        children.into_iter().next().unwrap().1
+    } else {
+        //ambiguous: several children have a valid cwd. A priority command always wins, no
+        //matter which child it is, so check for one before ever looking at the foreground
+        //process group.
+        match children.iter().position(|(_, cwd)| matches!(cwd, Cwd::Priority(_))) {
+            Some(index) => children.into_iter().nth(index).unwrap().1,
+            None => {
+                //no priority command among the children; prefer whichever one is the
+                //terminal's foreground process group instead, since that's the one actually
+                //being interacted with (e.g. a backgrounded editor plus a foreground shell
+                //command)
+                let foreground_cwd = foreground_pid(proc, pid).filter(|&tpgid| tpgid != pid)
+                                                               .and_then(|tpgid| get_child_cwd(proc, tpgid, priority_commands).ok());
+                match foreground_cwd {
+                    Some(foreground_cwd) => foreground_cwd,
+                    None => {
+                        //TODO: this isn't a problem if all children have the same cwd
+                        let (child_pid, child_cwd) = children.into_iter().next().unwrap();
+                        eprintln!("Warning: Process {} has multiple children. Following {}.", pid, child_pid);
+                        child_cwd
+                    }
+                }
+            }
+        }
     };
     match (&cwd, &child_cwd) {
         //return parent cwd if it has higher priority
@@ -204,20 +135,46 @@ fn get_child_cwd<Str: PartialEq<str>>(proc: &openat::Dir, pid: u32, priority_com
     }
 }
 
+//This binary runs synchronously to produce the cwd a shell is about to `cd` into, so a wedged
+//X server or compositor must not be able to stall the user's terminal indefinitely. Defaults to
+//500ms; override with RCWD_TIMEOUT_MS.
+fn lookup_timeout() -> std::time::Duration {
+    std::env::var("RCWD_TIMEOUT_MS").ok()
+        .and_then(|value| value.parse().ok())
+        .map(std::time::Duration::from_millis)
+        .unwrap_or(std::time::Duration::from_millis(500))
+}
+
 fn main() {
-    let cwd = get_proc_and_focused_window_pid().and_then(|(proc, pid)| {
-        get_child_cwd(&proc, pid, &std::env::args().skip(1).collect::<Vec<_>>()).and_then(|cwd| {
-            if cfg!(debug_assertions) {
-                let cwd = cwd.exists_or_err();
-                assert!(cwd.is_ok());
-                cwd
-            } else {
-                Ok(cwd)
-            }
-        }).map(|cwd| cwd.into())
-    }).unwrap_or_else(|error| {
-        eprintln!("{}", error);
-        dirs::home_dir().unwrap().into_os_string().into_string().unwrap()
+    let priority_commands = std::env::args().skip(1).collect::<Vec<_>>();
+
+    //the lookup talks to an external display server/compositor and can block indefinitely, so
+    //we run it on a worker thread and wait for it with a deadline; if it doesn't finish in time
+    //we abandon it and fall through to the same fallback as any other lookup failure. The
+    //thread may keep running past the deadline (e.g. still blocked on an unresponsive X server);
+    //that's fine, it'll be cleaned up with the rest of our state once this process exits.
+    let (sender, receiver) = std::sync::mpsc::channel();
+    std::thread::spawn(move || {
+        let result = window_system::detect().get_proc_and_focused_window_pid(&priority_commands).and_then(|(proc, pid)| {
+            get_child_cwd(&proc, pid, &priority_commands).and_then(|cwd| {
+                if cfg!(debug_assertions) {
+                    let cwd = cwd.exists_or_err();
+                    assert!(cwd.is_ok());
+                    cwd
+                } else {
+                    Ok(cwd)
+                }
+            }).map(|cwd| Into::<String>::into(cwd))
+        });
+        //the receiver may already have timed out and been dropped; nothing to do about that
+        let _ = sender.send(result);
     });
+
+    let cwd = receiver.recv_timeout(lookup_timeout())
+        .unwrap_or_else(|_| Err("Timed out waiting for the focused window's cwd.".to_string()))
+        .unwrap_or_else(|error| {
+            eprintln!("{}", error);
+            dirs::home_dir().unwrap().into_os_string().into_string().unwrap()
+        });
     println!("{}", cwd);
 }
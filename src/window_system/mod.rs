@@ -0,0 +1,34 @@
+mod wayland;
+mod x11;
+
+pub use wayland::Wayland;
+pub use x11::X11;
+
+//Abstracts "give me the focused window's pid" away from whichever display server is actually
+//running, the same way the old std::rt Runtime trait let a green or native scheduler be picked
+//behind one interface at process start. This lets main() stay display-server-agnostic.
+pub trait WindowSystem {
+    //Getting a handle for /proc and the pid of the focused window seem like orthogonal tasks -
+    //and they are - however, we want to avoid having our view of /proc being inconsistent with
+    //the window's pid. If we open /proc before we get the pid, but the program is started after
+    //we got our /proc-handle, we won't find the process with our handle (or potentially worse,
+    //scan a program that just exited and happened to have the same pid). If we open /proc after
+    //we get the pid, the program might be closed before we get our proc-handle, resulting in a
+    //similar unfortunate scenario. Implementors should open /proc after getting the window
+    //handle, but before getting its pid.
+    //
+    //priority_commands is passed through so that a backend which has to disambiguate between
+    //several candidate processes (e.g. the X11 WM_CLASS fallback) can prefer one the user asked
+    //to be prioritized, the same way get_child_cwd does for sibling processes.
+    fn get_proc_and_focused_window_pid(&self, priority_commands: &[String]) -> Result<(openat::Dir, u32), String>;
+}
+
+//Picks a backend based on which display server environment variables are set. Wayland takes
+//priority when both are present, e.g. under XWayland.
+pub fn detect() -> Box<dyn WindowSystem> {
+    if std::env::var_os("WAYLAND_DISPLAY").is_some() {
+        Box::new(Wayland)
+    } else {
+        Box::new(X11)
+    }
+}
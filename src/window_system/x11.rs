@@ -0,0 +1,149 @@
+use super::WindowSystem;
+
+#[allow(dead_code)]
+#[repr(u32)]
+enum X11WmState {
+    Withdrawn = 0,
+    Normal    = 1,
+    //             = 2,
+    Iconic    = 3,
+}
+
+pub struct X11;
+
+impl WindowSystem for X11 {
+    fn get_proc_and_focused_window_pid(&self, priority_commands: &[String]) -> Result<(openat::Dir, u32), String> {
+        //FIXME: X11 endianess?
+        use byteorder::{LittleEndian, ReadBytesExt};
+
+        let (conn, screen_num) = xcb::Connection::connect(None).map_err(|error| format!("Unable to open X11 connection: {}.", error))?;
+
+        let active_window_atom_cookie = xcb::intern_atom(&conn, false, "_NET_ACTIVE_WINDOW");
+        let pid_atom_cookie           = xcb::intern_atom(&conn, false, "_NET_WM_PID");
+        let state_atom_cookie         = xcb::intern_atom(&conn, false, "WM_STATE");
+
+        //get window
+        let root = conn.get_setup().roots().nth(screen_num as usize)
+                       .ok_or_else(|| "Unable to select current screen.".to_string())?.root();
+
+        let active_window_atom = active_window_atom_cookie.get_reply().map_err(|error| format!("Unable to retrieve _NET_ACTIVE_WINDOW atom: {}.", error))?.atom();
+
+        let reply = xcb::get_property(&conn, false, root, active_window_atom, xcb::ATOM_WINDOW, 0, 1)
+                        .get_reply()
+                        .map_err(|error| format!("Unable to retrieve _NET_ACTIVE_WINDOW property from root: {}.", error))?;
+        if reply.value_len() == 0 {
+            return Err("Unable to retrieve _NET_ACTIVE_WINDOW property from root.".to_string());
+        }
+        assert_eq!(reply.value_len(), 1);
+        let mut raw = reply.value();
+        assert_eq!(raw.len(), 4, "_NET_ACTIVE_WINDOW property is expected to be at least 4 bytes.");
+        let window = raw.read_u32::<LittleEndian>().unwrap() as xcb::Window;
+        if window == xcb::WINDOW_NONE {
+            return Err("No window is focused".to_string());
+        }
+
+        //open proc
+        let proc = openat::Dir::open("/proc").map_err(|error| format!("Unable to open /proc: {}", error))?;
+
+        //check withdrawn state
+        let state_atom = state_atom_cookie.get_reply().map_err(|error| format!("Unable to retrieve WM_STATE atom: {}.", error))?.atom();
+
+        match xcb::get_property(&conn, false, window, state_atom, state_atom, 0, 1).get_reply() {
+            Ok(reply) => {
+                if reply.value_len() == 0 {
+                    eprintln!("Unable to retrieve WM_STATE from focused window {}.", window);
+                }
+                else {
+                    assert_eq!(reply.value_len(), 1);
+                    let mut raw = reply.value();
+                    assert_eq!(raw.len(), 4, "WM_STATE property is expected to be at least 4 bytes.");
+                    let state = raw.read_u32::<LittleEndian>().unwrap();
+                    if state != X11WmState::Normal as u32 {
+                        return Err(format!("Focused window {} is not in normal (visible) state ({} != {}); Ignoring.", window, state, X11WmState::Normal as u32));
+                    }
+                }
+            }
+            Err(error) => {
+                eprintln!("Unable to retrieve WM_STATE from focused window {}: {}", window, error);
+            }
+        };
+
+        //get pid
+        let pid_atom = pid_atom_cookie.get_reply().map_err(|error| format!("Unable to retrieve _NET_WM_PID: {}.", error))?.atom();
+
+        let reply = xcb::get_property(&conn, false, window, pid_atom, xcb::ATOM_CARDINAL, 0, 1)
+                        .get_reply()
+                        .unwrap_or_else(|error| panic!("Unable to retrieve _NET_WM_PID from focused window {}: {}", window, error));
+        if reply.value_len() == 0 {
+            eprintln!("Unable to retrieve _NET_WM_PID from focused window {}; trying WM_CLASS.", window);
+            //TODO: what's a good size here?
+            let reply = xcb::get_property(&conn, false, window, xcb::ATOM_WM_CLASS, xcb::ATOM_STRING, 0, 64)
+                            .get_reply()
+                            .unwrap_or_else(|error| panic!("Unable to retrieve WM_CLASS from focused window {}: {}", window, error));
+            //WM_CLASS is two NUL-separated strings: the instance name, then the class name
+            let raw = reply.value() as &[u8];
+            let names = raw.split(|&byte| byte == 0u8)
+                            .filter(|part| !part.is_empty())
+                            .map(|part| String::from_utf8_lossy(part).into_owned())
+                            .collect::<Vec<_>>();
+            if names.is_empty() {
+                return Err(format!("Unable to decode WM_CLASS from focused window {}.", window));
+            }
+
+            let pid = find_process_by_names(&proc, &names, priority_commands)?;
+            return Ok((proc, pid));
+        }
+        assert_eq!(reply.value_len(), 1);
+        let mut raw = reply.value();
+        assert_eq!(raw.len(), 4, "_NET_WM_PID property is expected to be at least 4 bytes");
+        Ok((proc, raw.read_u32::<LittleEndian>().unwrap()))
+    }
+}
+
+//WM_CLASS gives us a name, not a pid, so when a window doesn't advertise _NET_WM_PID we fall
+//back to scanning /proc for a process whose comm (or exe basename) matches the instance or
+//class portion of WM_CLASS. Ties between several matching processes (e.g. two terminals with
+//the same WM_CLASS instance) are broken here, the same way get_child_cwd breaks ties between
+//siblings: a priority command wins, and otherwise the first match with a readable cwd does.
+fn find_process_by_names(proc: &openat::Dir, names: &[String], priority_commands: &[String]) -> Result<u32, String> {
+    let entries = proc.list_dir(".").map_err(|error| format!("Unable to list /proc: {}.", error))?;
+    let mut candidates = entries.filter_map(|entry| entry.ok())
+                                 .filter_map(|entry| entry.file_name().to_str().and_then(|name| name.parse::<u32>().ok()))
+                                 .filter(|&pid| process_matches_names(proc, pid, names))
+                                 .filter(|&pid| proc.read_link(format!("{}/cwd", pid))
+                                                     .map(|cwd| std::path::Path::new(&cwd).exists())
+                                                     .unwrap_or(false));
+
+    let mut first = None;
+    for pid in &mut candidates {
+        if is_priority_process(proc, pid, priority_commands) {
+            return Ok(pid);
+        }
+        first.get_or_insert(pid);
+    }
+    first.ok_or_else(|| format!("No running process matches WM_CLASS {:?}.", names))
+}
+
+fn is_priority_process(proc: &openat::Dir, pid: u32, priority_commands: &[String]) -> bool {
+    proc.read_link(format!("{}/exe", pid)).ok()
+        .and_then(|exe| exe.to_str().map(|exe| priority_commands.iter().any(|command| command == exe)))
+        .unwrap_or(false)
+}
+
+fn process_matches_names(proc: &openat::Dir, pid: u32, names: &[String]) -> bool {
+    use std::io::Read;
+
+    let mut comm = String::new();
+    let comm_matches = proc.open_file(format!("{}/comm", pid)).ok()
+                            .and_then(|mut file| file.read_to_string(&mut comm).ok())
+                            .map(|_| names.iter().any(|name| name == comm.trim_end()))
+                            .unwrap_or(false);
+    if comm_matches {
+        return true;
+    }
+
+    proc.read_link(format!("{}/exe", pid)).ok()
+        .and_then(|exe| exe.file_name().map(|name| name.to_owned()))
+        .and_then(|name| name.to_str().map(|name| names.iter().any(|candidate| candidate == name)))
+        .unwrap_or(false)
+}